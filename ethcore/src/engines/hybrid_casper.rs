@@ -16,15 +16,18 @@
 
 //! Hybrid Casper related functionalities.
 
+use std::cell::RefCell;
+use std::collections::HashMap;
 use bytes::Bytes;
 use ethereum_types::{Address, U256, H256};
 use engines::{DEFAULT_CASPER_CONTRACT, DEFAULT_PURITY_CHECKER_CONTRACT, DEFAULT_MSG_HASHER_CONTRACT, DEFAULT_RLP_DECODER_CONTRACT};
+use ethabi::{self, ParamType};
+use rlp::Rlp;
 use rustc_hex::FromHex;
 use transaction::{SignedTransaction, Action};
 use vm::Schedule;
 use state::{State, Backend};
 use types::BlockNumber;
-use ethabi::{self, ParamType};
 use super::SystemCall;
 
 use_contract!(simple_casper, "SimpleCasper", "res/contracts/simple_casper.json");
@@ -69,6 +72,8 @@ pub struct HybridCasperParams {
 	pub warm_up_period: u64,
 	/// Min deposit to consider a block to be justified.
 	pub non_revert_min_deposits: U256,
+	/// Upper bound on vote gas reimbursed within a single epoch.
+	pub vote_gas_refund_cap: U256,
 }
 
 impl From<::ethjson::spec::HybridCasperParams> for HybridCasperParams {
@@ -111,6 +116,7 @@ impl From<::ethjson::spec::HybridCasperParams> for HybridCasperParams {
 			min_deposit_size: p.min_deposit_size.map_or(U256::from(5) * ::ethereum::ether(), Into::into),
 			warm_up_period: p.warm_up_period.map_or(5, Into::into),
 			non_revert_min_deposits: p.non_revert_min_deposits.map_or(U256::from(1) * ::ethereum::ether(), Into::into),
+			vote_gas_refund_cap: p.vote_gas_refund_cap.map_or(U256::from(8000000), Into::into),
 		}
 	}
 }
@@ -145,9 +151,89 @@ impl Default for HybridCasperMetadata {
 	}
 }
 
+#[derive(Debug, Clone, PartialEq)]
+struct VoteMessage {
+	validator_index: U256,
+	target_hash: H256,
+	target_epoch: U256,
+	source_epoch: U256,
+	signature: Bytes,
+}
+
+impl VoteMessage {
+	fn decode(encoded: &[u8]) -> Result<Self, String> {
+		let rlp = Rlp::new(encoded);
+		Ok(VoteMessage {
+			validator_index: rlp.val_at(0).map_err(|e| format!("{}", e))?,
+			target_hash: rlp.val_at(1).map_err(|e| format!("{}", e))?,
+			target_epoch: rlp.val_at(2).map_err(|e| format!("{}", e))?,
+			source_epoch: rlp.val_at(3).map_err(|e| format!("{}", e))?,
+			signature: rlp.val_at(4).map_err(|e| format!("{}", e))?,
+		})
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ObservedVote {
+	source_epoch: U256,
+	target_epoch: U256,
+	target_hash: H256,
+	encoded: Bytes,
+}
+
+#[derive(Debug, Default)]
+struct SlashingDetector {
+	votes: HashMap<U256, Vec<ObservedVote>>,
+	pending: Vec<(Bytes, Bytes)>,
+}
+
+impl SlashingDetector {
+	fn observe(&mut self, vote: VoteMessage, encoded: Bytes) {
+		let history = self.votes.entry(vote.validator_index).or_insert_with(Vec::new);
+
+		let conflicts = history.iter().filter(|prior| {
+			(prior.target_epoch == vote.target_epoch && prior.target_hash != vote.target_hash) ||
+			(prior.source_epoch < vote.source_epoch && prior.target_epoch > vote.target_epoch) ||
+			(vote.source_epoch < prior.source_epoch && vote.target_epoch > prior.target_epoch)
+		}).cloned().collect::<Vec<_>>();
+
+		for conflict in conflicts {
+			self.pending.push((conflict.encoded, encoded.clone()));
+		}
+
+		history.push(ObservedVote {
+			source_epoch: vote.source_epoch,
+			target_epoch: vote.target_epoch,
+			target_hash: vote.target_hash,
+			encoded,
+		});
+	}
+
+	fn prune(&mut self, current_epoch: U256, withdrawal_delay: u64) {
+		let withdrawal_delay = U256::from(withdrawal_delay);
+		self.votes.retain(|_, history| {
+			history.retain(|vote| vote.target_epoch + withdrawal_delay >= current_epoch);
+			!history.is_empty()
+		});
+	}
+
+	fn take_pending(&mut self) -> Vec<(Bytes, Bytes)> {
+		::std::mem::replace(&mut self.pending, Vec::new())
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoteTransactionKind {
+	Legacy,
+	Typed {
+		type_byte: u8,
+	},
+}
+
 pub struct HybridCasper {
 	params: HybridCasperParams,
 	provider: simple_casper::SimpleCasper,
+	slashing: RefCell<SlashingDetector>,
 }
 
 impl HybridCasper {
@@ -155,40 +241,55 @@ impl HybridCasper {
 		Self {
 			params,
 			provider: simple_casper::SimpleCasper::default(),
+			slashing: RefCell::new(SlashingDetector::default()),
 		}
 	}
 
-	pub fn is_vote_transaction(&self, transaction: &SignedTransaction) -> bool {
+	pub fn is_vote_transaction(&self, transaction: &SignedTransaction) -> Option<VoteTransactionKind> {
 		if !transaction.is_unsigned() {
-			return false;
+			return None;
 		}
 
 		let unsigned = transaction.as_unsigned();
 
 		match unsigned.action {
-			Action::Create => {
-				return false;
-			},
+			Action::Create => return None,
 			Action::Call(address) => {
 				if address != self.params.contract_address {
-					return false;
+					return None;
 				}
 			},
 		}
 
-		if unsigned.data.len() < 4 {
-			return false;
+		if !unsigned.access_list.is_empty() {
+			return None;
 		}
 
-		if &unsigned.data[0..4] != &[0xe9, 0xdc, 0x06, 0x14] {
-			return false;
+		let vote_selector = self.provider.functions().vote().signature();
+		if unsigned.data.len() < 4 || &unsigned.data[0..4] != &vote_selector[..] {
+			return None;
 		}
 
-		return true;
+		match unsigned.transaction_type {
+			None => Some(VoteTransactionKind::Legacy),
+			Some(type_byte) => Some(VoteTransactionKind::Typed { type_byte }),
+		}
 	}
 
-	pub fn enable_casper_schedule(&self, schedule: &mut Schedule) {
+	pub fn enable_casper_schedule(&self, schedule: &mut Schedule, base_fee_per_gas: Option<U256>) {
 		schedule.eip86 = true;
+		schedule.eip1559 = base_fee_per_gas.is_some();
+	}
+
+	pub fn finalize_vote_gas(&self, base_fee_per_gas: Option<U256>, gas_used: U256, metadata: &mut HybridCasperMetadata) -> U256 {
+		let refund_room = self.params.vote_gas_refund_cap.saturating_sub(metadata.vote_gas_used);
+		let reimbursed = ::std::cmp::min(gas_used, refund_room);
+		metadata.vote_gas_used = metadata.vote_gas_used + reimbursed;
+
+		match base_fee_per_gas {
+			Some(base_fee_per_gas) => gas_used * base_fee_per_gas,
+			None => U256::zero(),
+		}
 	}
 
 	pub fn init_state<B: Backend>(&self, state: &mut State<B>) -> Result<(), ::error::Error> {
@@ -227,11 +328,13 @@ impl HybridCasper {
 			.map_err(Into::into)
 	}
 
-	pub fn on_new_epoch(&self, block_number: BlockNumber, caller: &mut SystemCall) -> Result<(), ::error::Error> {
+	pub fn on_new_epoch(&self, block_number: BlockNumber, metadata: &mut HybridCasperMetadata, caller: &mut SystemCall) -> Result<(), ::error::Error> {
 		if block_number % self.params.epoch_length == 0 {
-			let data = self.provider.functions().initialize_epoch().input(
-				block_number / self.params.epoch_length
-			);
+			let epoch = U256::from(block_number / self.params.epoch_length);
+			self.slashing.borrow_mut().prune(epoch, self.params.withdrawal_delay);
+			metadata.vote_gas_used = U256::zero();
+
+			let data = self.provider.functions().initialize_epoch().input(epoch);
 			caller(self.params.contract_address, data)
 				.map(|_| ())
 				.map_err(::engines::EngineError::FailedSystemCall)
@@ -241,17 +344,48 @@ impl HybridCasper {
 		}
 	}
 
+	pub fn observe_vote_transaction(&self, transaction: &SignedTransaction) -> Result<(), ::error::Error> {
+		if self.is_vote_transaction(transaction).is_none() {
+			return Ok(());
+		}
+
+		self.decode_and_observe_vote(&transaction.as_unsigned().data)
+			.map_err(::engines::EngineError::FailedSystemCall)
+			.map_err(Into::into)
+	}
+
+	fn decode_and_observe_vote(&self, data: &[u8]) -> Result<(), String> {
+		let encoded: Bytes = ethabi::decode(&[ParamType::Bytes], &data[4..])
+			.map_err(|e| format!("{}", e))?[0].clone()
+			.to_bytes()
+			.expect("type checked by ethabi::decode; qed")
+			.into();
+
+		let vote = VoteMessage::decode(&encoded)?;
+		self.slashing.borrow_mut().observe(vote, encoded);
+
+		Ok(())
+	}
+
+	pub fn pending_slashings(&self) -> Vec<(Bytes, Bytes)> {
+		self.slashing.borrow_mut().take_pending()
+	}
+
+	pub fn slash(&self, vote_msg_1: Bytes, vote_msg_2: Bytes, caller: &mut SystemCall) -> Result<(), ::error::Error> {
+		let data = self.provider.functions().slash().input(vote_msg_1, vote_msg_2);
+		caller(self.params.contract_address, data)
+			.map(|_| ())
+			.map_err(::engines::EngineError::FailedSystemCall)
+			.map_err(Into::into)
+	}
+
 	pub fn highest_justified_epoch(&self, caller: &mut SystemCall) -> Result<U256, ::error::Error> {
 		let data = self.provider.functions().highest_justified_epoch().input(
 			self.params.non_revert_min_deposits,
 		);
 		caller(self.params.contract_address, data)
-			.and_then(|output| {
-				Ok(ethabi::decode(&[ParamType::Int(128)], &output)
-				   .map_err(|e| format!("{}", e))?[0].clone()
-				   .to_int()
-				   .expect("type checked by ethabi::decode; qed"))
-			})
+			.and_then(|output| self.provider.functions().highest_justified_epoch().output(&output)
+				.map_err(|e| format!("{}", e)))
 			.map_err(::engines::EngineError::FailedSystemCall)
 			.map_err(Into::into)
 	}
@@ -261,12 +395,8 @@ impl HybridCasper {
 			self.params.non_revert_min_deposits,
 		);
 		caller(self.params.contract_address, data)
-			.and_then(|output| {
-				Ok(ethabi::decode(&[ParamType::Int(128)], &output)
-				   .map_err(|e| format!("{}", e))?[0].clone()
-				   .to_int()
-				   .expect("type checked by ethabi::decode; qed"))
-			})
+			.and_then(|output| self.provider.functions().highest_finalized_epoch().output(&output)
+				.map_err(|e| format!("{}", e)))
 			.map_err(::engines::EngineError::FailedSystemCall)
 			.map_err(Into::into)
 	}
@@ -276,12 +406,8 @@ impl HybridCasper {
 			epoch,
 		);
 		caller(self.params.contract_address, data)
-			.and_then(|output| {
-				Ok(H256::from_slice(&ethabi::decode(&[ParamType::FixedBytes(32)], &output)
-									.map_err(|e| format!("{}", e))?[0].clone()
-									.to_fixed_bytes()
-									.expect("type checked by ethabi::decode; qed")))
-			})
+			.and_then(|output| self.provider.functions().checkpoint_hashes().output(&output)
+				.map_err(|e| format!("{}", e)))
 			.map_err(::engines::EngineError::FailedSystemCall)
 			.map_err(Into::into)
 	}
@@ -293,4 +419,269 @@ impl HybridCasper {
 
 		Ok(())
 	}
-}
\ No newline at end of file
+
+	pub fn finalized_checkpoint_height(&self, metadata: &HybridCasperMetadata) -> BlockNumber {
+		metadata.highest_finalized_epoch.as_u64() * self.params.epoch_length
+	}
+
+	pub fn should_reject<F>(&self, ancestor_at: F, metadata: &HybridCasperMetadata) -> bool
+		where F: FnOnce(BlockNumber) -> Option<H256>
+	{
+		if metadata.highest_finalized_epoch.is_zero() {
+			return false;
+		}
+
+		let checkpoint_height = self.finalized_checkpoint_height(metadata);
+		match ancestor_at(checkpoint_height) {
+			Some(hash) => hash != metadata.highest_finalized_hash,
+			None => true,
+		}
+	}
+
+	pub fn score_justification(&self, metadata: &HybridCasperMetadata) -> U256 {
+		metadata.highest_justified_epoch
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use transaction::Transaction;
+
+	fn h256(byte: u8) -> H256 {
+		H256::from([byte; 32])
+	}
+
+	fn vote(validator_index: u64, source_epoch: u64, target_epoch: u64, target_hash: H256) -> VoteMessage {
+		VoteMessage {
+			validator_index: U256::from(validator_index),
+			target_hash,
+			target_epoch: U256::from(target_epoch),
+			source_epoch: U256::from(source_epoch),
+			signature: vec![],
+		}
+	}
+
+	#[test]
+	fn detects_double_vote() {
+		let mut detector = SlashingDetector::default();
+		let first = vec![1u8];
+		let second = vec![2u8];
+
+		detector.observe(vote(1, 1, 2, h256(1)), first.clone());
+		detector.observe(vote(1, 1, 2, h256(2)), second.clone());
+
+		assert_eq!(detector.take_pending(), vec![(first, second)]);
+	}
+
+	#[test]
+	fn detects_surround_vote() {
+		let mut detector = SlashingDetector::default();
+		let inner = vec![10u8];
+		let outer = vec![20u8];
+
+		detector.observe(vote(7, 3, 4, h256(1)), inner.clone());
+		detector.observe(vote(7, 1, 5, h256(2)), outer.clone());
+
+		assert_eq!(detector.take_pending(), vec![(inner, outer)]);
+	}
+
+	#[test]
+	fn non_conflicting_votes_are_not_slashable() {
+		let mut detector = SlashingDetector::default();
+
+		detector.observe(vote(3, 1, 2, h256(1)), vec![1]);
+		detector.observe(vote(3, 3, 4, h256(2)), vec![2]);
+
+		assert!(detector.take_pending().is_empty());
+	}
+
+	#[test]
+	fn different_validators_never_conflict() {
+		let mut detector = SlashingDetector::default();
+
+		detector.observe(vote(1, 1, 2, h256(1)), vec![1]);
+		detector.observe(vote(2, 1, 2, h256(2)), vec![2]);
+
+		assert!(detector.take_pending().is_empty());
+	}
+
+	#[test]
+	fn queues_every_conflicting_pair() {
+		let mut detector = SlashingDetector::default();
+
+		detector.observe(vote(1, 1, 2, h256(1)), vec![1]);
+		detector.observe(vote(1, 3, 4, h256(2)), vec![2]);
+		detector.observe(vote(1, 5, 2, h256(3)), vec![3]);
+
+		assert_eq!(detector.take_pending(), vec![
+			(vec![1], vec![3]),
+			(vec![2], vec![3]),
+		]);
+	}
+
+	#[test]
+	fn prune_drops_votes_older_than_withdrawal_delay() {
+		let mut detector = SlashingDetector::default();
+		detector.observe(vote(9, 1, 2, h256(1)), vec![1]);
+
+		detector.prune(U256::from(100), 10);
+
+		assert!(detector.votes.is_empty());
+	}
+
+	#[test]
+	fn prune_keeps_votes_within_withdrawal_delay() {
+		let mut detector = SlashingDetector::default();
+		detector.observe(vote(9, 94, 95, h256(1)), vec![1]);
+
+		detector.prune(U256::from(100), 10);
+
+		assert_eq!(detector.votes.get(&U256::from(9)).map(|v| v.len()), Some(1));
+	}
+
+	fn casper_with_epoch_length(epoch_length: u64) -> HybridCasper {
+		let mut params = HybridCasperParams::default();
+		params.epoch_length = epoch_length;
+		HybridCasper::new(params)
+	}
+
+	#[test]
+	fn accepts_everything_before_first_finalization() {
+		let casper = casper_with_epoch_length(5);
+		let metadata = HybridCasperMetadata::default();
+
+		assert!(!casper.should_reject(|_| None, &metadata));
+	}
+
+	#[test]
+	fn rejects_candidate_that_reverts_finalized_checkpoint() {
+		let casper = casper_with_epoch_length(5);
+		let mut metadata = HybridCasperMetadata::default();
+		metadata.highest_finalized_epoch = U256::from(2);
+		metadata.highest_finalized_hash = h256(9);
+
+		// Checkpoint height is epoch 2 * epoch_length 5 == 10; the candidate's ancestor there
+		// is a different hash, so it has reverted the finalized checkpoint.
+		assert!(casper.should_reject(|height| {
+			assert_eq!(height, 10);
+			Some(h256(1))
+		}, &metadata));
+	}
+
+	#[test]
+	fn accepts_candidate_that_includes_finalized_checkpoint() {
+		let casper = casper_with_epoch_length(5);
+		let mut metadata = HybridCasperMetadata::default();
+		metadata.highest_finalized_epoch = U256::from(2);
+		metadata.highest_finalized_hash = h256(9);
+
+		assert!(!casper.should_reject(|height| {
+			assert_eq!(height, 10);
+			Some(h256(9))
+		}, &metadata));
+	}
+
+	#[test]
+	fn rejects_candidate_shorter_than_the_checkpoint_height() {
+		let casper = casper_with_epoch_length(5);
+		let mut metadata = HybridCasperMetadata::default();
+		metadata.highest_finalized_epoch = U256::from(2);
+		metadata.highest_finalized_hash = h256(9);
+
+		assert!(casper.should_reject(|_| None, &metadata));
+	}
+
+	fn casper_with_contract(contract_address: Address) -> HybridCasper {
+		let mut params = HybridCasperParams::default();
+		params.contract_address = contract_address;
+		HybridCasper::new(params)
+	}
+
+	fn calldata_with_selector(selector: &[u8], tail_len: usize) -> Bytes {
+		let mut data = selector.to_vec();
+		data.extend(vec![0u8; tail_len]);
+		data
+	}
+
+	fn null_signed(action: Action, data: Bytes, transaction_type: Option<u8>) -> SignedTransaction {
+		Transaction {
+			nonce: U256::zero(),
+			gas_price: U256::zero(),
+			gas: U256::zero(),
+			action,
+			value: U256::zero(),
+			data,
+			transaction_type,
+			access_list: Vec::new(),
+		}.null_sign(0)
+	}
+
+	#[test]
+	fn recognizes_legacy_vote_transaction() {
+		let contract = Address::from(0x40u64);
+		let casper = casper_with_contract(contract);
+		let selector = casper.provider.functions().vote().signature();
+		let data = calldata_with_selector(&selector[..], 32);
+
+		let tx = null_signed(Action::Call(contract), data, None);
+
+		assert_eq!(casper.is_vote_transaction(&tx), Some(VoteTransactionKind::Legacy));
+	}
+
+	#[test]
+	fn recognizes_typed_vote_transaction() {
+		let contract = Address::from(0x40u64);
+		let casper = casper_with_contract(contract);
+		let selector = casper.provider.functions().vote().signature();
+		let data = calldata_with_selector(&selector[..], 32);
+
+		let tx = null_signed(Action::Call(contract), data, Some(2));
+
+		assert_eq!(casper.is_vote_transaction(&tx), Some(VoteTransactionKind::Typed { type_byte: 2 }));
+	}
+
+	#[test]
+	fn rejects_malformed_short_calldata() {
+		let contract = Address::from(0x40u64);
+		let casper = casper_with_contract(contract);
+
+		let tx = null_signed(Action::Call(contract), vec![0xe9, 0xdc, 0x06], None);
+
+		assert_eq!(casper.is_vote_transaction(&tx), None);
+	}
+
+	#[test]
+	fn rejects_wrong_selector() {
+		let contract = Address::from(0x40u64);
+		let casper = casper_with_contract(contract);
+
+		let tx = null_signed(Action::Call(contract), vec![0, 0, 0, 0, 1, 2, 3], None);
+
+		assert_eq!(casper.is_vote_transaction(&tx), None);
+	}
+
+	#[test]
+	fn rejects_wrong_contract_address() {
+		let contract = Address::from(0x40u64);
+		let casper = casper_with_contract(contract);
+		let selector = casper.provider.functions().vote().signature();
+		let data = calldata_with_selector(&selector[..], 32);
+
+		let tx = null_signed(Action::Call(Address::from(0x99u64)), data, None);
+
+		assert_eq!(casper.is_vote_transaction(&tx), None);
+	}
+
+	#[test]
+	fn rejects_contract_creation() {
+		let contract = Address::from(0x40u64);
+		let casper = casper_with_contract(contract);
+		let selector = casper.provider.functions().vote().signature();
+		let data = calldata_with_selector(&selector[..], 32);
+
+		let tx = null_signed(Action::Create, data, None);
+
+		assert_eq!(casper.is_vote_transaction(&tx), None);
+	}
+}